@@ -1,3 +1,5 @@
+use crate::forest::{Forest, PackedKey};
+use crate::interned::{InternedDottedRule, InternedEdge, RuleId, RuleTable};
 use crate::tree::Tree;
 use std::{
     collections::{HashMap, HashSet, VecDeque},
@@ -5,9 +7,15 @@ use std::{
     hash::Hash,
 };
 
-pub trait Terminal: Clone + Copy + Eq + Hash {}
-
-impl<T> Terminal for T where T: Clone + Copy + Eq + Hash {}
+pub trait Terminal: Clone + Copy + Eq + Hash {
+    /// Whether this grammar terminal matches a given input token. Defaults
+    /// to exact equality, so existing exact-match terminals need no changes;
+    /// override it to match a class of tokens (e.g. "any digit") instead of
+    /// one concrete token, and Scan will consult this instead of `==`.
+    fn matches(&self, token: &Self) -> bool {
+        self == token
+    }
+}
 
 pub trait Nonterminal: Clone + Copy + Eq + Hash {
     /// Returns the starting non-terminal
@@ -94,29 +102,13 @@ where
     N: Nonterminal,
     T: Terminal,
 {
-    fn next_symbol(&self) -> Option<Symbol<N, T>> {
-        if self.dot_pos >= self.production.rhs.len() {
-            None
-        } else {
-            Some(self.production.rhs[self.dot_pos].clone())
-        }
-    }
-
-    fn advanced_dot(mut self) -> Self {
-        if self.dot_pos >= self.production.rhs.len() {
-            panic!("Attempted to advance dot when nowhere to advance to");
-        }
-        self.dot_pos += 1;
-        self
-    }
-
-    pub fn is_complete(&self) -> bool {
-        self.dot_pos >= self.production.rhs.len()
-    }
-
     pub fn production(&self) -> &Production<N, T> {
         &self.production
     }
+
+    pub(crate) fn from_parts(production: Production<N, T>, dot_pos: usize) -> Self {
+        production.into_dotted_rule(dot_pos)
+    }
 }
 
 impl<N, T> Display for DottedRule<N, T>
@@ -151,7 +143,6 @@ where
     dotted_rule: DottedRule<N, T>,
     start: usize,
     end: usize,
-    history: Vec<ChartEdge<N, T>>,
 }
 
 impl<N, T> ChartEdge<N, T>
@@ -171,27 +162,12 @@ where
         self.end
     }
 
-    pub fn history(&self) -> &Vec<ChartEdge<N, T>> {
-        &self.history
-    }
-
-    pub fn generate_derivation_tree(&self) -> Tree<Symbol<N, T>> {
-        let mut children: Vec<Tree<Symbol<N, T>>> = self
-            .history()
-            .into_iter()
-            .map(Self::generate_derivation_tree)
-            .collect();
-
-        for sym in self.dotted_rule().production().rhs() {
-            if let Symbol::Terminal(t) = sym {
-                children.push(Tree::new(Symbol::Terminal(t.clone()), vec![]))
-            }
+    pub(crate) fn from_parts(dotted_rule: DottedRule<N, T>, start: usize, end: usize) -> Self {
+        Self {
+            dotted_rule,
+            start,
+            end,
         }
-
-        Tree::new(
-            Symbol::Nonterminal(self.dotted_rule().production().lhs().clone()),
-            children,
-        )
     }
 }
 
@@ -202,21 +178,41 @@ where
 {
     /// String to parse
     input_string: Vec<T>,
-    /// Maps from nonterminals to its productions
-    productions_by_lhs: HashMap<N, Vec<Production<N, T>>>,
+    /// Every distinct production, interned once so edges can reference it by
+    /// a small `RuleId` instead of cloning its rhs on every dot-advance.
+    rule_table: RuleTable<N, T>,
+    /// Maps from nonterminals to the rules (in `rule_table`) with that lhs
+    productions_by_lhs: HashMap<N, Vec<RuleId>>,
     /// All edges in a set for quick member check
-    all_edges: HashSet<ChartEdge<N, T>>,
+    all_edges: HashSet<InternedEdge>,
     /// Edges left to predict/scan/complete
-    to_process: VecDeque<ChartEdge<N, T>>,
+    to_process: VecDeque<InternedEdge>,
+
+    /// Edges awaiting a given nonterminal at a given position, i.e. the
+    /// edges an instance of that nonterminal completing there would advance.
+    /// Keeps Complete a single hash lookup instead of a scan over every edge.
+    waiting_on: HashMap<(usize, N), Vec<InternedEdge>>,
+
+    /// Nonterminals already predicted at a given position, so Predict never
+    /// re-expands the same nonterminal's productions twice there.
+    predicted: HashMap<usize, HashSet<N>>,
+
+    /// Nonterminals that can derive the empty string, precomputed once by
+    /// fixpoint (Aycock-Horspool). Lets Predict eagerly advance past them
+    /// instead of waiting on a Complete event that may never re-fire.
+    nullable: HashSet<N>,
 
     /// Complete derivations stored here
-    complete_derivations: Vec<ChartEdge<N, T>>,
+    complete_derivations: Vec<InternedEdge>,
+
+    /// Shared packed parse forest: every derivation any edge below
+    /// contributes to is recorded here instead of on the edge itself, so
+    /// ambiguous spans are shared rather than copied.
+    forest: Forest<N, T>,
 
-    /// Entire chart in order (mainly just for printing it out),
-    /// the second item in the pair is the history in the form of indices
-    /// back into this `Vec`, as this is easier to print in a table.
-    /// This will only be populated if trace is true
-    trace_chart: Vec<(ChartEdge<N, T>, Vec<usize>)>,
+    /// Entire chart in order (mainly just for printing it out). This will
+    /// only be populated if trace is true.
+    trace_chart: Vec<InternedEdge>,
     trace: bool,
 }
 
@@ -227,68 +223,122 @@ where
 {
     /// Create new chart
     pub fn new(input_string: Vec<T>, productions: Vec<Production<N, T>>) -> Self {
-        let mut productions_by_lhs = HashMap::new();
-        let mut to_process = VecDeque::new();
-        let mut all_edges = HashSet::new();
+        let mut rule_table = RuleTable::new();
+        let mut productions_by_lhs: HashMap<N, Vec<RuleId>> = HashMap::new();
 
         for production in productions {
-            let prods = productions_by_lhs
-                .entry(production.lhs)
-                .or_insert_with(Vec::new);
+            let lhs = *production.lhs();
+            let rule_id = rule_table.intern(production);
 
-            prods.push(production.clone());
+            productions_by_lhs
+                .entry(lhs)
+                .or_insert_with(Vec::new)
+                .push(rule_id);
         }
 
-        for production in productions_by_lhs
+        let starting_rules = productions_by_lhs
             .get(&N::start())
             .expect("No starting productions")
-        {
-            let edge = ChartEdge {
-                dotted_rule: production.clone().into_dotted_rule(0),
-                start: 0,
-                end: 0,
-                history: Vec::new(),
-            };
-            to_process.push_back(edge.clone());
-            all_edges.insert(edge);
-        }
+            .clone();
 
-        Self {
-            input_string: input_string,
+        let nullable = Self::compute_nullable(&productions_by_lhs, &rule_table);
+
+        let mut chart = Self {
+            input_string,
+            rule_table,
             productions_by_lhs,
-            all_edges,
-            to_process,
+            all_edges: HashSet::new(),
+            to_process: VecDeque::new(),
+            waiting_on: HashMap::new(),
+            predicted: HashMap::new(),
+            nullable,
             trace_chart: Vec::new(),
             trace: false,
             complete_derivations: Vec::new(),
+            forest: Forest::new(),
+        };
+
+        chart
+            .predicted
+            .entry(0)
+            .or_insert_with(HashSet::new)
+            .insert(N::start());
+
+        for rule_id in starting_rules {
+            let edge = InternedEdge::new(InternedDottedRule::new(rule_id), 0, 0);
+
+            // Mirrors the empty-rhs registration in the Predict branch: a
+            // nullable start-symbol alternative completes immediately here,
+            // with no dot-advance to register it with the forest later.
+            if chart.rule_table.get(rule_id).rhs().is_empty() {
+                let key = PackedKey::new(rule_id, 0, 0, 0);
+                chart.forest.add_packing(&chart.rule_table, key, None, None);
+            }
+
+            chart.add_edge(edge);
+        }
+
+        chart
+    }
+
+    /// Computes, by fixpoint, every nonterminal that can derive the empty
+    /// string: either directly via an empty-rhs production, or transitively
+    /// via a production whose entire rhs is nullable nonterminals.
+    fn compute_nullable(
+        productions_by_lhs: &HashMap<N, Vec<RuleId>>,
+        rule_table: &RuleTable<N, T>,
+    ) -> HashSet<N> {
+        let mut nullable = HashSet::new();
+        let mut changed = true;
+
+        while changed {
+            changed = false;
+
+            for (lhs, rule_ids) in productions_by_lhs {
+                if nullable.contains(lhs) {
+                    continue;
+                }
+
+                let is_nullable = rule_ids.iter().any(|&rule_id| {
+                    rule_table.get(rule_id).rhs().iter().all(|symbol| match symbol {
+                        Symbol::Nonterminal(n) => nullable.contains(n),
+                        Symbol::Terminal(_) => false,
+                    })
+                });
+
+                if is_nullable {
+                    nullable.insert(*lhs);
+                    changed = true;
+                }
+            }
         }
+
+        nullable
     }
 
     pub fn set_trace(&mut self, trace: bool) {
         self.trace = trace;
     }
 
-    fn add_to_trace_chart(&mut self, edge: &ChartEdge<N, T>) {
+    fn add_to_trace_chart(&mut self, edge: InternedEdge) {
         if self.trace {
-            let history: Vec<usize> = edge
-                .history()
-                .iter()
-                .map(|e| {
-                    for (j, (oe, _)) in self.trace_chart.iter().enumerate() {
-                        if e == oe {
-                            return j;
-                        }
-                    }
-                    return usize::MAX;
-                })
-                .collect();
-
-            self.trace_chart.push((edge.clone(), history));
+            self.trace_chart.push(edge);
         }
     }
 
-    pub fn trace_chart(&self) -> &Vec<(ChartEdge<N, T>, Vec<usize>)> {
-        &self.trace_chart
+    /// The entire chart in edge-processed order. Only populated if
+    /// [`Chart::set_trace`] was enabled before parsing.
+    pub fn trace_chart(&self) -> Vec<ChartEdge<N, T>> {
+        self.trace_chart
+            .iter()
+            .map(|edge| edge.to_chart_edge(&self.rule_table))
+            .collect()
+    }
+
+    /// The shared packed parse forest built up so far. Complete once
+    /// [`Chart::process_all`] (or enough of [`Chart::process_one`]) has run.
+    pub fn parse_forest(&self) -> &Forest<N, T> {
+        &self.forest
     }
 
     pub fn process_all(&mut self) {
@@ -304,108 +354,324 @@ where
     /// Processes one edge from to_process. Panics if nothing to do.
     pub fn process_one(&mut self) -> ChartEdge<N, T> {
         if let Some(edge) = self.to_process.pop_front() {
-            match edge.dotted_rule.next_symbol() {
+            match edge.rule().next_symbol(&self.rule_table) {
                 // Predict
                 Some(Symbol::Nonterminal(nonterminal)) => {
-                    let productions = self
-                        .productions_by_lhs
-                        .get(&nonterminal)
-                        .expect("Expected non-terminal to have a production");
+                    let newly_predicted = self
+                        .predicted
+                        .entry(edge.end())
+                        .or_insert_with(HashSet::new)
+                        .insert(nonterminal);
+
+                    if newly_predicted {
+                        let rule_ids = self
+                            .productions_by_lhs
+                            .get(&nonterminal)
+                            .expect("Expected non-terminal to have a production")
+                            .clone();
+
+                        let new_edges: Vec<InternedEdge> = rule_ids
+                            .iter()
+                            .map(|&rule_id| {
+                                InternedEdge::new(
+                                    InternedDottedRule::new(rule_id),
+                                    edge.end(),
+                                    edge.end(),
+                                )
+                            })
+                            .collect();
+
+                        // An empty-rhs production is complete the moment it's
+                        // predicted, having matched nothing, so register it
+                        // with the forest directly instead of waiting for a
+                        // dot-advance that will never come.
+                        for new_edge in &new_edges {
+                            let rule_id = new_edge.rule().rule_id();
+                            if self.rule_table.get(rule_id).rhs().is_empty() {
+                                let key = PackedKey::new(rule_id, 0, edge.end(), edge.end());
+                                self.forest.add_packing(&self.rule_table, key, None, None);
+                            }
+                        }
 
-                    let new_edges: Vec<ChartEdge<N, T>> = productions
-                        .iter()
-                        .map(|production| ChartEdge {
-                            dotted_rule: production.clone().into_dotted_rule(0),
-                            start: edge.end,
-                            end: edge.end,
-                            history: Vec::new(),
-                        })
-                        .collect();
+                        self.add_edges(new_edges);
+                    }
 
-                    self.add_edges(new_edges);
+                    // Aycock-Horspool: a nullable nonterminal may already
+                    // have finished completing at this position before this
+                    // edge started waiting on it, so that Complete event
+                    // won't fire again. Eagerly advance past it here instead
+                    // of relying on one to.
+                    if self.nullable.contains(&nonterminal) {
+                        let new_edge =
+                            InternedEdge::new(edge.rule().advanced(), edge.start(), edge.end());
+
+                        let left = if edge.rule().dot_pos() == 0 {
+                            None
+                        } else {
+                            Some(edge.as_packed_key())
+                        };
+                        let right =
+                            Some((Symbol::Nonterminal(nonterminal), edge.end(), edge.end()));
+                        let key = PackedKey::new(
+                            new_edge.rule().rule_id(),
+                            new_edge.rule().dot_pos(),
+                            new_edge.start(),
+                            new_edge.end(),
+                        );
+                        self.forest.add_packing(&self.rule_table, key, left, right);
+
+                        self.add_edge(new_edge);
+                    }
                 }
                 // Scan
                 Some(Symbol::Terminal(terminal)) => {
-                    if self.input_string.get(edge.end) == Some(&terminal) {
-                        let new_edge = ChartEdge {
-                            dotted_rule: edge.dotted_rule.clone().advanced_dot(),
-                            start: edge.start,
-                            end: edge.end + 1,
-                            history: Vec::new(),
+                    if let Some(&token) = self
+                        .input_string
+                        .get(edge.end())
+                        .filter(|token| terminal.matches(token))
+                    {
+                        let new_edge = InternedEdge::new(
+                            edge.rule().advanced(),
+                            edge.start(),
+                            edge.end() + 1,
+                        );
+
+                        let left = if edge.rule().dot_pos() == 0 {
+                            None
+                        } else {
+                            Some(edge.as_packed_key())
                         };
+                        // Record the concrete token that was actually scanned
+                        // (not the grammar's terminal, which may be a class),
+                        // so derivation trees show the real input at the leaf.
+                        let right = Some((Symbol::Terminal(token), edge.end(), edge.end() + 1));
+                        let key = PackedKey::new(
+                            new_edge.rule().rule_id(),
+                            new_edge.rule().dot_pos(),
+                            new_edge.start(),
+                            new_edge.end(),
+                        );
+                        self.forest.add_packing(&self.rule_table, key, left, right);
 
                         self.add_edge(new_edge);
                     }
                 }
                 // Complete
                 None => {
-                    let completed_nonterminal = edge.dotted_rule.production.lhs;
+                    let completed_nonterminal =
+                        *self.rule_table.get(edge.rule().rule_id()).lhs();
 
                     if completed_nonterminal == N::start()
                         && edge.start() == 0
                         && edge.end() == self.input_string.len()
                     {
-                        self.complete_derivations.push(edge.clone());
+                        self.complete_derivations.push(edge);
                     }
 
-                    let new_edges: Vec<ChartEdge<N, T>> = self
-                        .all_edges
+                    let waiting_edges = self
+                        .waiting_on
+                        .get(&(edge.start(), completed_nonterminal))
+                        .cloned()
+                        .unwrap_or_default();
+
+                    let new_edges: Vec<InternedEdge> = waiting_edges
                         .iter()
-                        .filter_map(|other_edge| {
-                            if Some(Symbol::Nonterminal(completed_nonterminal))
-                                == other_edge.dotted_rule.next_symbol()
-                                && other_edge.end == edge.start
-                            {
-                                let mut new_hist = other_edge.history.clone();
-                                new_hist.push(edge.clone());
-
-                                Some(ChartEdge {
-                                    dotted_rule: other_edge.dotted_rule.clone().advanced_dot(),
-                                    start: other_edge.start,
-                                    end: edge.end,
-                                    history: new_hist,
-                                })
-                            } else {
-                                None
-                            }
+                        .map(|other_edge| {
+                            InternedEdge::new(
+                                other_edge.rule().advanced(),
+                                other_edge.start(),
+                                edge.end(),
+                            )
                         })
                         .collect();
 
+                    for new_edge in &new_edges {
+                        let left = if new_edge.rule().dot_pos() == 1 {
+                            None
+                        } else {
+                            Some(PackedKey::new(
+                                new_edge.rule().rule_id(),
+                                new_edge.rule().dot_pos() - 1,
+                                new_edge.start(),
+                                edge.start(),
+                            ))
+                        };
+                        let right = Some((
+                            Symbol::Nonterminal(completed_nonterminal),
+                            edge.start(),
+                            edge.end(),
+                        ));
+                        let key = PackedKey::new(
+                            new_edge.rule().rule_id(),
+                            new_edge.rule().dot_pos(),
+                            new_edge.start(),
+                            new_edge.end(),
+                        );
+                        self.forest.add_packing(&self.rule_table, key, left, right);
+                    }
+
                     self.add_edges(new_edges);
                 }
             }
 
-            return edge;
+            return edge.to_chart_edge(&self.rule_table);
         } else {
             panic!("No processing left!");
         }
     }
 
-    fn add_edge(&mut self, new_edge: ChartEdge<N, T>) {
+    fn add_edge(&mut self, new_edge: InternedEdge) {
         if !self.all_edges.contains(&new_edge) {
-            self.add_to_trace_chart(&new_edge);
-            self.to_process.push_back(new_edge.clone());
+            self.add_to_trace_chart(new_edge);
+
+            if let Some(Symbol::Nonterminal(awaited)) = new_edge.rule().next_symbol(&self.rule_table)
+            {
+                self.waiting_on
+                    .entry((new_edge.end(), awaited))
+                    .or_insert_with(Vec::new)
+                    .push(new_edge);
+            }
+
+            self.to_process.push_back(new_edge);
             self.all_edges.insert(new_edge);
         }
     }
 
     fn add_edges<I>(&mut self, new_edges: I)
     where
-        I: IntoIterator<Item = ChartEdge<N, T>>,
+        I: IntoIterator<Item = InternedEdge>,
     {
         for new_edge in new_edges {
             self.add_edge(new_edge);
         }
     }
 
-    pub fn complete_derivations(&self) -> &Vec<ChartEdge<N, T>> {
-        &self.complete_derivations
+    pub fn complete_derivations(&self) -> Vec<ChartEdge<N, T>> {
+        self.complete_derivations
+            .iter()
+            .map(|edge| edge.to_chart_edge(&self.rule_table))
+            .collect()
     }
 
+    /// Eagerly unpacks every derivation the forest holds for a completed
+    /// parse. For ambiguous grammars this enumerates the same exponential
+    /// number of trees as before; use [`Chart::parse_forest`] and
+    /// [`Forest::derivations`] directly to consume derivations lazily
+    /// instead.
     pub fn generate_derivation_trees(&self) -> Vec<Tree<Symbol<N, T>>> {
+        // Every completed derivation shares the same (N::start(), 0, input
+        // length) symbol key by construction (that's exactly what the
+        // Complete branch filters on), so without deduping this re-derives
+        // the whole forest's derivation set once per top-level alternative
+        // instead of once overall.
+        let mut seen_keys = HashSet::new();
+
         self.complete_derivations()
             .iter()
-            .map(|e| e.generate_derivation_tree())
+            .map(|edge| (*edge.dotted_rule().production().lhs(), edge.start(), edge.end()))
+            .filter(|key| seen_keys.insert(*key))
+            .flat_map(|(lhs, start, end)| self.forest.derivations(lhs, start, end))
             .collect()
     }
 }
+
+impl<N, T> Chart<N, T>
+where
+    N: Nonterminal + Display,
+    T: Terminal + Display,
+{
+    /// Renders the traced chart as Graphviz DOT, one node per edge labeled
+    /// with its `DottedRule` and span. Requires `set_trace(true)` to have
+    /// been set before parsing, otherwise this renders an empty graph.
+    ///
+    /// This no longer draws history back-edges: since the SPPF rework,
+    /// edges don't carry derivation history themselves, so there's nothing
+    /// to draw one from. For visualizing ambiguity and sharing between
+    /// derivations, use [`Chart::parse_forest`] and [`Forest::to_dot`].
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph Chart {\n  node [fontsize=10, shape=box];\n");
+
+        for (i, edge) in self.trace_chart.iter().enumerate() {
+            let dotted_rule = edge.rule().to_dotted_rule(&self.rule_table);
+            dot.push_str(&format!(
+                "  e{} [label=\"{} [{}, {})\"];\n",
+                i,
+                escape_dot_label(&dotted_rule.to_string()),
+                edge.start(),
+                edge.end()
+            ));
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+fn escape_dot_label(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `char` so these impls can't collide with `main`'s `impl Nonterminal
+    // for &'static str` in the same test binary.
+    impl Nonterminal for char {
+        fn start() -> Self {
+            'S'
+        }
+    }
+
+    impl Terminal for char {}
+
+    fn parse_count(productions: Vec<Production<char, char>>, input: Vec<char>) -> usize {
+        let mut chart: Chart<char, char> = Chart::new(input, productions);
+        chart.process_all();
+        chart.generate_derivation_trees().len()
+    }
+
+    #[test]
+    fn nullable_left_recursive_chain() {
+        // S -> A; A -> A a | ε
+        let productions = vec![
+            Production::new('S', vec![Symbol::Nonterminal('A')]),
+            Production::new('A', vec![Symbol::Nonterminal('A'), Symbol::Terminal('a')]),
+            Production::new('A', vec![]),
+        ];
+        assert_eq!(parse_count(productions, vec!['a', 'a', 'a']), 1);
+    }
+
+    #[test]
+    fn nullable_start_symbol() {
+        // S -> ε | a
+        let productions = vec![
+            Production::new('S', vec![]),
+            Production::new('S', vec![Symbol::Terminal('a')]),
+        ];
+        assert_eq!(parse_count(productions, vec![]), 1);
+    }
+
+    #[test]
+    fn mutually_nullable_pair() {
+        // S -> A B; A -> ε; B -> ε
+        let productions = vec![
+            Production::new('S', vec![Symbol::Nonterminal('A'), Symbol::Nonterminal('B')]),
+            Production::new('A', vec![]),
+            Production::new('B', vec![]),
+        ];
+        assert_eq!(parse_count(productions, vec![]), 1);
+    }
+
+    #[test]
+    fn ambiguous_top_level_alternatives_are_not_duplicated() {
+        // S -> A | B; A -> a; B -> a
+        let productions = vec![
+            Production::new('S', vec![Symbol::Nonterminal('A')]),
+            Production::new('S', vec![Symbol::Nonterminal('B')]),
+            Production::new('A', vec![Symbol::Terminal('a')]),
+            Production::new('B', vec![Symbol::Terminal('a')]),
+        ];
+        assert_eq!(parse_count(productions, vec!['a']), 2);
+    }
+}
@@ -0,0 +1,387 @@
+use crate::earley::{Nonterminal, Symbol, Terminal};
+use crate::interned::{RuleId, RuleTable};
+use crate::tree::Tree;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+
+/// Identifies a symbol node in the forest: the terminal or nonterminal
+/// spanning `[start, end)` of the input.
+pub type SymbolKey<N, T> = (Symbol<N, T>, usize, usize);
+
+/// Identifies a packed node: one way of having matched the production
+/// behind `rule_id` up to `dot_pos` over `[start, end)`. The same key also
+/// stands in for the "intermediate" (not yet complete) nodes that chain
+/// packed nodes together so that a production with more than two
+/// right-hand-side symbols can be represented without duplicating the
+/// subtrees it shares with other derivations. Plain ints plus a `RuleId`
+/// handle, so it's `Copy` and never needs the full `Production` cloned just
+/// to identify a node.
+#[derive(Clone, Copy, Eq, Hash, PartialEq)]
+pub struct PackedKey {
+    rule_id: RuleId,
+    dot_pos: usize,
+    start: usize,
+    end: usize,
+}
+
+impl PackedKey {
+    pub(crate) fn new(rule_id: RuleId, dot_pos: usize, start: usize, end: usize) -> Self {
+        Self {
+            rule_id,
+            dot_pos,
+            start,
+            end,
+        }
+    }
+}
+
+/// One way of reaching a packed/intermediate node: everything matched
+/// before `right` (the same production one dot position back), plus the
+/// symbol that this dot advance consumed. Both are `None` only for an
+/// empty-rhs production, which completes having matched nothing at all.
+#[derive(Clone, Eq, PartialEq)]
+struct PackedNode<N, T>
+where
+    N: Nonterminal,
+    T: Terminal,
+{
+    left: Option<PackedKey>,
+    right: Option<SymbolKey<N, T>>,
+}
+
+/// A shared packed parse forest (SPPF). Symbol nodes, keyed by `(Symbol,
+/// start, end)`, fan out to one packed node per distinct production that
+/// derives that span; packed nodes in turn chain together one right-hand-side
+/// symbol at a time so that ambiguity anywhere in a derivation is recorded
+/// once and shared by every parse that passes through it, instead of being
+/// copied into every `Tree` that needs it.
+#[derive(Clone)]
+pub struct Forest<N, T>
+where
+    N: Nonterminal,
+    T: Terminal,
+{
+    packed: HashMap<PackedKey, Vec<PackedNode<N, T>>>,
+    symbol_keys: HashMap<SymbolKey<N, T>, Vec<PackedKey>>,
+}
+
+impl<N, T> Forest<N, T>
+where
+    N: Nonterminal,
+    T: Terminal,
+{
+    pub(crate) fn new() -> Self {
+        Self {
+            packed: HashMap::new(),
+            symbol_keys: HashMap::new(),
+        }
+    }
+
+    /// Records that advancing the production behind `key`'s rule to
+    /// `key`'s dot position over `[key.start, key.end)` consumed `right`,
+    /// continuing on from `left` (the packed/intermediate node one dot
+    /// position back, or `None` if `right` is the first symbol on the
+    /// right-hand side). `right` is `None` only when the production has an
+    /// empty right-hand side, which completes immediately having matched
+    /// nothing.
+    pub(crate) fn add_packing(
+        &mut self,
+        rule_table: &RuleTable<N, T>,
+        key: PackedKey,
+        left: Option<PackedKey>,
+        right: Option<SymbolKey<N, T>>,
+    ) -> PackedKey {
+        let production = rule_table.get(key.rule_id);
+        let is_complete = key.dot_pos == production.rhs().len();
+        let lhs = *production.lhs();
+
+        let node = PackedNode { left, right };
+        let nodes = self.packed.entry(key).or_insert_with(Vec::new);
+        if !nodes.contains(&node) {
+            nodes.push(node);
+        }
+
+        if is_complete {
+            let symbol_key = (Symbol::Nonterminal(lhs), key.start, key.end);
+            let keys = self.symbol_keys.entry(symbol_key).or_insert_with(Vec::new);
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+
+        key
+    }
+
+    fn count_symbol(
+        &self,
+        key: &SymbolKey<N, T>,
+        visiting: &mut HashSet<SymbolKey<N, T>>,
+        memo: &RefCell<HashMap<SymbolKey<N, T>, u64>>,
+    ) -> u64 {
+        if let Symbol::Terminal(_) = key.0 {
+            return 1;
+        }
+        if let Some(count) = memo.borrow().get(key) {
+            return *count;
+        }
+        // Cycle guard: a nonterminal that (directly or indirectly) depends on
+        // itself over the same span can't contribute another derivation
+        // without looping forever, so treat the re-entrant path as a dead end.
+        if !visiting.insert(key.clone()) {
+            return 0;
+        }
+
+        let count = self
+            .symbol_keys
+            .get(key)
+            .map(|keys| {
+                keys.iter()
+                    .map(|packed_key| self.count_packed(packed_key, visiting, memo))
+                    .sum()
+            })
+            .unwrap_or(0);
+
+        visiting.remove(key);
+        memo.borrow_mut().insert(key.clone(), count);
+        count
+    }
+
+    fn count_packed(
+        &self,
+        key: &PackedKey,
+        visiting: &mut HashSet<SymbolKey<N, T>>,
+        memo: &RefCell<HashMap<SymbolKey<N, T>, u64>>,
+    ) -> u64 {
+        self.packed
+            .get(key)
+            .map(|nodes| {
+                nodes
+                    .iter()
+                    .map(|node| {
+                        let left_count = match &node.left {
+                            Some(left_key) => self.count_packed(left_key, visiting, memo),
+                            None => 1,
+                        };
+                        let right_count = match &node.right {
+                            Some(right_key) => self.count_symbol(right_key, visiting, memo),
+                            None => 1,
+                        };
+                        left_count * right_count
+                    })
+                    .sum()
+            })
+            .unwrap_or(0)
+    }
+
+    fn nth_symbol(
+        &self,
+        key: &SymbolKey<N, T>,
+        mut n: u64,
+        memo: &RefCell<HashMap<SymbolKey<N, T>, u64>>,
+    ) -> Tree<Symbol<N, T>> {
+        if let Symbol::Terminal(t) = &key.0 {
+            return Tree::new(Symbol::Terminal(*t), Vec::new());
+        }
+
+        let keys = self
+            .symbol_keys
+            .get(key)
+            .expect("derivation index requested for a symbol not in the forest");
+
+        for packed_key in keys {
+            let count = self.count_packed(packed_key, &mut HashSet::new(), memo);
+            if n < count {
+                let children = self.nth_packed(packed_key, n, memo);
+                return Tree::new(key.0.clone(), children);
+            }
+            n -= count;
+        }
+
+        panic!("derivation index out of range");
+    }
+
+    fn nth_packed(
+        &self,
+        key: &PackedKey,
+        mut n: u64,
+        memo: &RefCell<HashMap<SymbolKey<N, T>, u64>>,
+    ) -> Vec<Tree<Symbol<N, T>>> {
+        let nodes = self
+            .packed
+            .get(key)
+            .expect("derivation index requested for a packed node not in the forest");
+
+        for node in nodes {
+            let left_count = match &node.left {
+                Some(left_key) => self.count_packed(left_key, &mut HashSet::new(), memo),
+                None => 1,
+            };
+            let right_count = match &node.right {
+                Some(right_key) => self.count_symbol(right_key, &mut HashSet::new(), memo),
+                None => 1,
+            };
+            let total = left_count * right_count;
+
+            if n < total {
+                let left_index = n / right_count;
+                let right_index = n % right_count;
+
+                let mut children = match &node.left {
+                    Some(left_key) => self.nth_packed(left_key, left_index, memo),
+                    None => Vec::new(),
+                };
+                if let Some(right_key) = &node.right {
+                    children.push(self.nth_symbol(right_key, right_index, memo));
+                }
+                return children;
+            }
+            n -= total;
+        }
+
+        panic!("derivation index out of range");
+    }
+
+    /// Lazily unpacks every derivation of `symbol` over `[start, end)` into a
+    /// `Tree`. Derivations are only built as they're iterated, so ambiguous
+    /// parses that are never asked for are never materialized.
+    pub fn derivations(&self, symbol: N, start: usize, end: usize) -> Derivations<'_, N, T> {
+        let key = (Symbol::Nonterminal(symbol), start, end);
+        let memo = RefCell::new(HashMap::new());
+        let total = self.count_symbol(&key, &mut HashSet::new(), &memo);
+
+        Derivations {
+            forest: self,
+            key,
+            memo,
+            next_index: 0,
+            total,
+        }
+    }
+}
+
+/// Iterator over the derivations of a symbol node, produced on demand by
+/// [`Forest::derivations`].
+pub struct Derivations<'f, N, T>
+where
+    N: Nonterminal,
+    T: Terminal,
+{
+    forest: &'f Forest<N, T>,
+    key: SymbolKey<N, T>,
+    memo: RefCell<HashMap<SymbolKey<N, T>, u64>>,
+    next_index: u64,
+    total: u64,
+}
+
+impl<'f, N, T> Iterator for Derivations<'f, N, T>
+where
+    N: Nonterminal,
+    T: Terminal,
+{
+    type Item = Tree<Symbol<N, T>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next_index >= self.total {
+            return None;
+        }
+
+        let tree = self.forest.nth_symbol(&self.key, self.next_index, &self.memo);
+        self.next_index += 1;
+        Some(tree)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = (self.total - self.next_index) as usize;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<N, T> Forest<N, T>
+where
+    N: Nonterminal + Display,
+    T: Terminal + Display,
+{
+    /// Renders the whole forest as Graphviz DOT, mirroring its own
+    /// structure: one ellipse/box per symbol node, one small diamond per
+    /// production (or intermediate) node, and one point per packed
+    /// alternative under it, whether or not there is more than one. A symbol
+    /// or production node with more than one child is exactly the
+    /// sharing/ambiguity this forest exists to show.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph Forest {\n  node [fontsize=10];\n");
+
+        for (symbol_key, productions) in &self.symbol_keys {
+            let symbol_id = node_id("sym", symbol_key);
+            let shape = match &symbol_key.0 {
+                Symbol::Terminal(_) => "box",
+                Symbol::Nonterminal(_) => "ellipse",
+            };
+            dot.push_str(&format!(
+                "  {} [label=\"{} [{}, {})\", shape={}];\n",
+                symbol_id, symbol_key.0, symbol_key.1, symbol_key.2, shape
+            ));
+
+            for production_key in productions {
+                let production_id = node_id("prod", production_key);
+                dot.push_str(&format!(
+                    "  {} [label=\"\", shape=diamond];\n",
+                    production_id
+                ));
+                dot.push_str(&format!("  {} -> {};\n", symbol_id, production_id));
+
+                self.write_packed_alternatives(&mut dot, production_key);
+            }
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+
+    fn write_packed_alternatives(&self, dot: &mut String, key: &PackedKey) {
+        let Some(nodes) = self.packed.get(key) else {
+            return;
+        };
+        let production_id = node_id("prod", key);
+
+        for (index, node) in nodes.iter().enumerate() {
+            let packed_id = format!("{}_{}", production_id, index);
+            dot.push_str(&format!("  {} [label=\"\", shape=point];\n", packed_id));
+            dot.push_str(&format!("  {} -> {};\n", production_id, packed_id));
+
+            if let Some(left_key) = &node.left {
+                let left_id = node_id("prod", left_key);
+                dot.push_str(&format!(
+                    "  {} [label=\"\", shape=diamond];\n",
+                    left_id
+                ));
+                dot.push_str(&format!("  {} -> {} [label=\"left\"];\n", packed_id, left_id));
+                self.write_packed_alternatives(dot, left_key);
+            }
+
+            if let Some(right_key) = &node.right {
+                let right_id = node_id("sym", right_key);
+                let shape = match &right_key.0 {
+                    Symbol::Terminal(_) => "box",
+                    Symbol::Nonterminal(_) => "ellipse",
+                };
+                dot.push_str(&format!(
+                    "  {} [label=\"{} [{}, {})\", shape={}];\n",
+                    right_id, right_key.0, right_key.1, right_key.2, shape
+                ));
+                dot.push_str(&format!("  {} -> {} [label=\"right\"];\n", packed_id, right_id));
+            }
+        }
+    }
+}
+
+/// Builds a stable Graphviz node id out of anything `Hash`, so the same key
+/// always maps to the same node without needing a separate id table.
+fn node_id<K: std::hash::Hash>(prefix: &str, key: &K) -> String {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::Hasher;
+
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    format!("{}_{:x}", prefix, hasher.finish())
+}
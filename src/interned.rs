@@ -0,0 +1,150 @@
+use crate::earley::{ChartEdge, DottedRule, Nonterminal, Production, Symbol, Terminal};
+use crate::forest::PackedKey;
+use std::collections::HashMap;
+
+/// A small integer handle for a [`Production`], so chart edges can reference
+/// a rule instead of embedding (and re-cloning, on every dot-advance) its
+/// whole `Vec<Symbol>` rhs.
+#[derive(Clone, Copy, Eq, Hash, PartialEq)]
+pub struct RuleId(u32);
+
+/// Interns productions behind [`RuleId`]s, deduplicating identical ones so
+/// every distinct production gets exactly one id and is cloned only when a
+/// caller needs to hand a full [`Production`] back out (e.g. in a
+/// [`DottedRule`] for a [`ChartEdge`]).
+pub struct RuleTable<N, T>
+where
+    N: Nonterminal,
+    T: Terminal,
+{
+    productions: Vec<Production<N, T>>,
+    ids: HashMap<Production<N, T>, RuleId>,
+}
+
+impl<N, T> RuleTable<N, T>
+where
+    N: Nonterminal,
+    T: Terminal,
+{
+    pub(crate) fn new() -> Self {
+        Self {
+            productions: Vec::new(),
+            ids: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn intern(&mut self, production: Production<N, T>) -> RuleId {
+        if let Some(&id) = self.ids.get(&production) {
+            return id;
+        }
+
+        let id = RuleId(self.productions.len() as u32);
+        self.productions.push(production.clone());
+        self.ids.insert(production, id);
+        id
+    }
+
+    pub(crate) fn get(&self, id: RuleId) -> &Production<N, T> {
+        &self.productions[id.0 as usize]
+    }
+}
+
+/// The compact counterpart of [`DottedRule`]: a rule handle plus a dot
+/// position, both plain integers and `Copy`, so advancing a dot never
+/// clones a production's rhs.
+#[derive(Clone, Copy, Eq, Hash, PartialEq)]
+pub struct InternedDottedRule {
+    rule_id: RuleId,
+    dot: u16,
+}
+
+impl InternedDottedRule {
+    pub(crate) fn new(rule_id: RuleId) -> Self {
+        Self { rule_id, dot: 0 }
+    }
+
+    pub(crate) fn rule_id(&self) -> RuleId {
+        self.rule_id
+    }
+
+    pub(crate) fn dot_pos(&self) -> usize {
+        self.dot as usize
+    }
+
+    pub(crate) fn next_symbol<N, T>(&self, table: &RuleTable<N, T>) -> Option<Symbol<N, T>>
+    where
+        N: Nonterminal,
+        T: Terminal,
+    {
+        table
+            .get(self.rule_id)
+            .rhs()
+            .get(self.dot as usize)
+            .cloned()
+    }
+
+    pub(crate) fn advanced(mut self) -> Self {
+        self.dot += 1;
+        self
+    }
+
+    pub(crate) fn to_dotted_rule<N, T>(self, table: &RuleTable<N, T>) -> DottedRule<N, T>
+    where
+        N: Nonterminal,
+        T: Terminal,
+    {
+        DottedRule::from_parts(table.get(self.rule_id).clone(), self.dot as usize)
+    }
+}
+
+/// The compact counterpart of [`ChartEdge`]: four plain integers, `Copy`,
+/// with no embedded `Production`, so the chart's working sets (`all_edges`,
+/// `to_process`, `waiting_on`) can store and hash edges without allocating.
+#[derive(Clone, Copy, Eq, Hash, PartialEq)]
+pub struct InternedEdge {
+    rule: InternedDottedRule,
+    start: u32,
+    end: u32,
+}
+
+impl InternedEdge {
+    pub(crate) fn new(rule: InternedDottedRule, start: usize, end: usize) -> Self {
+        Self {
+            rule,
+            start: start as u32,
+            end: end as u32,
+        }
+    }
+
+    pub(crate) fn rule(&self) -> InternedDottedRule {
+        self.rule
+    }
+
+    pub(crate) fn start(&self) -> usize {
+        self.start as usize
+    }
+
+    pub(crate) fn end(&self) -> usize {
+        self.end as usize
+    }
+
+    /// The key this edge would occupy as a packed/intermediate forest node,
+    /// i.e. "everything matched so far" for the production it is partway
+    /// through.
+    pub(crate) fn as_packed_key(&self) -> PackedKey {
+        PackedKey::new(
+            self.rule.rule_id(),
+            self.rule.dot_pos(),
+            self.start(),
+            self.end(),
+        )
+    }
+
+    pub(crate) fn to_chart_edge<N, T>(self, table: &RuleTable<N, T>) -> ChartEdge<N, T>
+    where
+        N: Nonterminal,
+        T: Terminal,
+    {
+        ChartEdge::from_parts(self.rule.to_dotted_rule(table), self.start(), self.end())
+    }
+}
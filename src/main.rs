@@ -1,7 +1,7 @@
 mod earley;
-
-use core::num;
-use std::fmt::Display;
+mod forest;
+mod interned;
+mod tree;
 
 use earley::{Chart, ChartEdge, Nonterminal, Production, Symbol, Terminal};
 
@@ -14,6 +14,65 @@ impl Nonterminal for Nt {
     }
 }
 
+impl Terminal for T {
+    /// A terminal written as a bracket expression, e.g. `[a-z]+` or `[0-9]`,
+    /// matches a token made up of characters from that class (one or more
+    /// with a trailing `+`, exactly one without) instead of one exact token;
+    /// anything else still compares by exact equality.
+    fn matches(&self, token: &Self) -> bool {
+        if self.starts_with('[') {
+            matches_char_class(self, token)
+        } else {
+            self == token
+        }
+    }
+}
+
+/// A tiny regex subset for the `[a-z]+` / `[0-9]` terminal-class syntax:
+/// a bracket expression (optionally negated with a leading `^`, supporting
+/// `a-z` ranges) followed by an optional `+` quantifier, matched against
+/// every character of `token`. Not a general regex engine — just enough to
+/// say "any token made of these characters".
+fn matches_char_class(pattern: &str, token: &str) -> bool {
+    let Some(rest) = pattern.strip_prefix('[') else {
+        return false;
+    };
+    let Some(close) = rest.find(']') else {
+        return false;
+    };
+    let (class_body, quantifier) = (&rest[..close], &rest[close + 1..]);
+
+    let (negate, class_body) = match class_body.strip_prefix('^') {
+        Some(stripped) => (true, stripped),
+        None => (false, class_body),
+    };
+
+    let in_class = |c: char| -> bool {
+        let mut chars = class_body.chars().peekable();
+        let mut matched = false;
+        while let Some(lo) = chars.next() {
+            if chars.peek() == Some(&'-') {
+                chars.next();
+                if let Some(hi) = chars.next() {
+                    matched |= (lo..=hi).contains(&c);
+                    continue;
+                }
+            }
+            matched |= c == lo;
+        }
+        matched != negate
+    };
+
+    match quantifier {
+        "+" => !token.is_empty() && token.chars().all(in_class),
+        "" => {
+            let mut chars = token.chars();
+            matches!((chars.next(), chars.next()), (Some(c), None) if in_class(c))
+        }
+        _ => false,
+    }
+}
+
 fn parse_simple_prods(prods_text: &'static str) -> Vec<Production<Nt, T>> {
     prods_text
         .split("\n")
@@ -23,22 +82,29 @@ fn parse_simple_prods(prods_text: &'static str) -> Vec<Production<Nt, T>> {
             lhs = lhs.trim();
 
             rhses.trim().split("|").map(move |rhs_raw| {
-                let rhs: Vec<Symbol<Nt, T>> = rhs_raw
-                    .trim()
-                    .split(" ")
-                    .map(|sym| {
-                        if sym
-                            .chars()
-                            .nth(0)
-                            .expect("Failed simple parse (2)")
-                            .is_ascii_uppercase()
-                        {
-                            Symbol::Nonterminal(sym)
-                        } else {
-                            Symbol::Terminal(sym)
-                        }
-                    })
-                    .collect();
+                // An empty alternative (or the explicit marker "ε") is a
+                // nullable production: `A -> a |` and `A -> ε` both mean `A`
+                // can derive the empty string.
+                let rhs_raw = rhs_raw.trim();
+                let rhs: Vec<Symbol<Nt, T>> = if rhs_raw.is_empty() || rhs_raw == "ε" {
+                    Vec::new()
+                } else {
+                    rhs_raw
+                        .split(" ")
+                        .map(|sym| {
+                            if sym
+                                .chars()
+                                .nth(0)
+                                .expect("Failed simple parse (2)")
+                                .is_ascii_uppercase()
+                            {
+                                Symbol::Nonterminal(sym)
+                            } else {
+                                Symbol::Terminal(sym)
+                            }
+                        })
+                        .collect()
+                };
 
                 Production::new(lhs, rhs)
             })
@@ -46,95 +112,6 @@ fn parse_simple_prods(prods_text: &'static str) -> Vec<Production<Nt, T>> {
         .collect()
 }
 
-struct Tree {
-    node: Symbol<Nt, T>,
-    children: Vec<Tree>,
-}
-
-impl ToString for Tree {
-    fn to_string(&self) -> String {
-        if self.children.len() > 1 {
-            // Each subtree, but we reverse the rows so its easier to add to the end
-            let mut children_strings: Vec<Vec<String>> = self
-                .children
-                .iter()
-                .map(|t| {
-                    let mut subtree_strings: Vec<String> =
-                        t.to_string().split("\n").map(|s| s.to_owned()).collect();
-                    subtree_strings.reverse();
-                    subtree_strings
-                })
-                .collect();
-            let max_height = children_strings.iter().map(|s| s.len()).max().unwrap_or(0);
-
-            let mut branch_length = 0;
-            let children_strings_len = children_strings.len();
-
-            for (i, child_strings) in children_strings.iter_mut().enumerate() {
-                // This should mean every subtree vec in children_strings is the same length (max_height + 1)
-                for _ in 0..(max_height - child_strings.len() + 1) {
-                    child_strings.push("|".to_owned());
-                }
-
-                let max_subtree_width = child_strings.iter().map(|s| s.len()).max().unwrap_or(0);
-
-                let right_padding = if i == children_strings_len - 1 { 0 } else { 1 };
-
-                if i != children_strings_len - 1 {
-                    branch_length += max_subtree_width + right_padding;
-                }
-
-                for child_string in child_strings.iter_mut() {
-                    child_string.push_str(
-                        &" ".repeat(max_subtree_width - child_string.len() + right_padding),
-                    );
-                }
-            }
-
-            let branch_string = "|".to_owned() + &"_".repeat(branch_length - 1);
-
-            let mut lines: Vec<String> = Vec::new();
-
-            for i in (0..max_height + 1) {
-                let mut line = String::new();
-                for s in children_strings.iter() {
-                    line.push_str(&s[i]);
-                }
-                lines.push(line);
-            }
-
-            lines.push(branch_string);
-            lines.push(self.node.to_string().to_owned());
-
-            lines.reverse();
-
-            lines.join("\n")
-        } else if self.children.len() == 1 {
-            self.node.to_string() + "\n|\n" + &self.children[0].to_string()
-        } else {
-            self.node.to_string()
-        }
-    }
-}
-
-fn derivation_tree(deriv: &ChartEdge<Nt, T>) -> Tree {
-    let mut children: Vec<Tree> = deriv.history().into_iter().map(derivation_tree).collect();
-
-    for sym in deriv.dotted_rule().production().rhs() {
-        if let Symbol::Terminal(t) = sym {
-            children.push(Tree {
-                node: Symbol::Terminal(t),
-                children: vec![],
-            })
-        }
-    }
-
-    Tree {
-        node: Symbol::Nonterminal(deriv.dotted_rule().production().lhs()),
-        children,
-    }
-}
-
 fn main() {
     let productions = parse_simple_prods(
         " 
@@ -158,56 +135,86 @@ fn main() {
     );
 
     let input_string = vec!["they", "can", "fish", "in", "rivers", "in", "december"];
-    let input_string_len = input_string.len();
 
     let mut chart: Chart<Nt, T> = Chart::new(input_string, productions);
+    chart.set_trace(true);
 
     let mut chart_ordered: Vec<ChartEdge<Nt, T>> = Vec::new();
-    let mut complete_derivations: Vec<ChartEdge<Nt, T>> = Vec::new();
-    let mut num_parses = 0;
     while chart.more_to_process() {
-        let edge = chart.process_one();
-        chart_ordered.push(edge.clone());
-
-        if edge.dotted_rule().production().lhs() == &"S"
-            && edge.dotted_rule().is_complete()
-            && edge.start() == 0
-            && edge.end() == input_string_len
-        {
-            num_parses += 1;
-            complete_derivations.push(edge);
-        }
+        chart_ordered.push(chart.process_one());
     }
 
     for (i, edge) in chart_ordered.iter().enumerate() {
-        let history: String = edge
-            .history()
-            .iter()
-            .map(|e| {
-                for (j, oe) in chart_ordered.iter().enumerate() {
-                    if e == oe {
-                        return j.to_string();
-                    }
-                }
-                return "-1".to_owned();
-            })
-            .collect::<Vec<String>>()
-            .join(",");
-
         println!(
-            "{:3} | {:15} | {:3},{:3} | {}",
+            "{:3} | {:15} | {:3},{:3}",
             i,
             edge.dotted_rule(),
             edge.start(),
             edge.end(),
-            history
         );
     }
 
-    for derivation in complete_derivations {
-        println!("{}", derivation_tree(&derivation).to_string());
+    let derivation_trees = chart.generate_derivation_trees();
+    for tree in &derivation_trees {
+        println!("{}", tree);
         println!();
     }
 
-    println!("Num parses: {}", num_parses);
+    println!("Num parses: {}", derivation_trees.len());
+
+    if let Some(tree) = derivation_trees.first() {
+        println!("{}", tree.to_dot());
+    }
+    println!("{}", chart.parse_forest().to_dot());
+    println!("{}", chart.to_dot());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plus_quantifier_matches_one_or_more_class_chars() {
+        assert!(matches_char_class("[a-z]+", "fish"));
+        assert!(!matches_char_class("[a-z]+", ""));
+        assert!(!matches_char_class("[a-z]+", "Fish"));
+    }
+
+    #[test]
+    fn no_quantifier_matches_exactly_one_class_char() {
+        assert!(matches_char_class("[0-9]", "5"));
+        assert!(!matches_char_class("[0-9]", "50"));
+        assert!(!matches_char_class("[0-9]", ""));
+    }
+
+    #[test]
+    fn negated_class_matches_chars_outside_the_range() {
+        assert!(matches_char_class("[^a-z]+", "RIVER"));
+        assert!(!matches_char_class("[^a-z]+", "river"));
+    }
+
+    #[test]
+    fn non_class_pattern_never_matches() {
+        assert!(!matches_char_class("fish", "fish"));
+    }
+
+    #[test]
+    fn trailing_dash_in_class_is_treated_as_a_range_start_not_a_literal() {
+        // Known quirk: `a-` has no range endpoint after the `-`, so the
+        // parser falls through to matching the literal `a` and silently
+        // drops the `-` from the class instead of matching it or erroring.
+        assert!(matches_char_class("[a-]+", "a"));
+        assert!(!matches_char_class("[a-]+", "-"));
+    }
+
+    #[test]
+    fn terminal_matches_dispatches_class_patterns_and_exact_literals() {
+        let class: T = "[a-z]+";
+        assert!(Terminal::matches(&class, &"fish"));
+        assert!(!Terminal::matches(&class, &"FISH"));
+
+        let literal: T = "fish";
+        assert!(Terminal::matches(&literal, &"fish"));
+        assert!(!Terminal::matches(&literal, &"fishing"));
+    }
 }
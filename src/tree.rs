@@ -1,3 +1,4 @@
+use crate::earley::{Nonterminal, Symbol, Terminal};
 use std::fmt::Display;
 
 pub struct Tree<T> {
@@ -94,3 +95,47 @@ where
         )
     }
 }
+
+impl<N, T> Tree<Symbol<N, T>>
+where
+    N: Nonterminal + Display,
+    T: Terminal + Display,
+{
+    /// Renders this derivation tree as Graphviz DOT, with terminals drawn
+    /// as boxes and nonterminals as ellipses. Pipe the output into
+    /// `dot -Tsvg` to view it.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph DerivationTree {\n  node [fontsize=10];\n");
+        let mut next_id = 0;
+        self.write_dot_node(&mut dot, &mut next_id);
+        dot.push_str("}\n");
+        dot
+    }
+
+    fn write_dot_node(&self, dot: &mut String, next_id: &mut usize) -> usize {
+        let id = *next_id;
+        *next_id += 1;
+
+        let shape = match &self.value {
+            Symbol::Terminal(_) => "box",
+            Symbol::Nonterminal(_) => "ellipse",
+        };
+        dot.push_str(&format!(
+            "  n{} [label=\"{}\", shape={}];\n",
+            id,
+            escape_dot_label(&self.value.to_string()),
+            shape
+        ));
+
+        for child in &self.children {
+            let child_id = child.write_dot_node(dot, next_id);
+            dot.push_str(&format!("  n{} -> n{};\n", id, child_id));
+        }
+
+        id
+    }
+}
+
+fn escape_dot_label(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}